@@ -0,0 +1,144 @@
+//! A minimal HDR (high-dynamic-range) histogram.
+//!
+//! Values are decomposed into a power-of-two `bucket_index` and a linear
+//! `sub_bucket_index` within that band, so that a duration anywhere between a
+//! microsecond and an hour can be recorded in O(1) and later queried for a
+//! percentile with bounded relative error, without retaining every sample.
+
+const LOWEST_TRACKABLE_VALUE: u64 = 1;
+const HIGHEST_TRACKABLE_VALUE: u64 = 3_600_000_000_000; // one hour, in nanoseconds
+
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    unit_magnitude: u32,
+    sub_bucket_half_count_magnitude: u32,
+    sub_bucket_half_count: u32,
+    sub_bucket_count: u32,
+    sub_bucket_mask: u64,
+    bucket_count: u32,
+    counts: Vec<u64>,
+    total_count: u64,
+    min: u64,
+    max: u64,
+    sum: u64,
+}
+
+impl Histogram {
+    /// Creates a histogram that keeps `significant_figures` decimal digits of
+    /// resolution (e.g. `3` means values are accurate to within 0.1%).
+    pub fn new(significant_figures: u8) -> Self {
+        let largest_value_with_single_unit_resolution = 2 * 10u64.pow(significant_figures as u32);
+        let sub_bucket_count_magnitude = (largest_value_with_single_unit_resolution as f64).log2().ceil() as u32;
+        let sub_bucket_half_count_magnitude = sub_bucket_count_magnitude.saturating_sub(1);
+        let unit_magnitude = (LOWEST_TRACKABLE_VALUE as f64).log2().floor() as u32;
+        let sub_bucket_count = 1u32 << sub_bucket_count_magnitude;
+        let sub_bucket_half_count = sub_bucket_count / 2;
+        let sub_bucket_mask = (sub_bucket_count as u64 - 1) << unit_magnitude;
+
+        let mut bucket_count = 1;
+        let mut smallest_untrackable_value = (sub_bucket_count as u64) << unit_magnitude;
+        while smallest_untrackable_value < HIGHEST_TRACKABLE_VALUE {
+            smallest_untrackable_value <<= 1;
+            bucket_count += 1;
+        }
+
+        let counts_len = ((bucket_count + 1) * sub_bucket_half_count) as usize;
+        Self {
+            unit_magnitude,
+            sub_bucket_half_count_magnitude,
+            sub_bucket_half_count,
+            sub_bucket_count,
+            sub_bucket_mask,
+            bucket_count,
+            counts: vec![0; counts_len],
+            total_count: 0,
+            min: u64::MAX,
+            max: 0,
+            sum: 0,
+        }
+    }
+
+    /// Records a single value, clamped to `[1, HIGHEST_TRACKABLE_VALUE]` so
+    /// that a duration longer than the histogram was sized for still lands in
+    /// the top cell instead of indexing out of bounds.
+    #[inline]
+    pub fn record(&mut self, value: u64) {
+        let value = value.clamp(1, HIGHEST_TRACKABLE_VALUE);
+        let index = self.counts_index(value);
+        self.counts[index] += 1;
+        self.total_count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn total_count(&self) -> u64 {
+        self.total_count
+    }
+
+    pub fn min(&self) -> u64 {
+        if self.total_count == 0 {
+            0
+        } else {
+            self.min
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.total_count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.total_count as f64
+        }
+    }
+
+    /// Returns the representative value of the cell containing `percentile`
+    /// (0.0..=100.0), found by walking cells in ascending magnitude order
+    /// until the target rank is reached.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        if self.total_count == 0 {
+            return 0;
+        }
+        let target = ((percentile / 100.0) * self.total_count as f64).ceil().max(1.0) as u64;
+        let mut accumulated = 0u64;
+        for bucket_index in 0..=self.bucket_count {
+            let sub_start = if bucket_index == 0 { 0 } else { self.sub_bucket_half_count };
+            for sub_bucket_index in sub_start..self.sub_bucket_count {
+                let index = self.index_for(bucket_index, sub_bucket_index);
+                accumulated += self.counts[index];
+                if accumulated >= target {
+                    return self.value_from(bucket_index, sub_bucket_index);
+                }
+            }
+        }
+        self.max
+    }
+
+    fn bucket_index(&self, value: u64) -> u32 {
+        let pow2_ceiling = 64 - (value | self.sub_bucket_mask).leading_zeros();
+        pow2_ceiling.saturating_sub(self.unit_magnitude + self.sub_bucket_half_count_magnitude + 1)
+    }
+
+    fn sub_bucket_index(&self, value: u64, bucket_index: u32) -> u32 {
+        (value >> (bucket_index + self.unit_magnitude)) as u32
+    }
+
+    fn index_for(&self, bucket_index: u32, sub_bucket_index: u32) -> usize {
+        let bucket_base_index = (bucket_index as i64 + 1) << self.sub_bucket_half_count_magnitude;
+        (bucket_base_index + sub_bucket_index as i64 - self.sub_bucket_half_count as i64) as usize
+    }
+
+    fn counts_index(&self, value: u64) -> usize {
+        let bucket_index = self.bucket_index(value);
+        let sub_bucket_index = self.sub_bucket_index(value, bucket_index);
+        self.index_for(bucket_index, sub_bucket_index)
+    }
+
+    fn value_from(&self, bucket_index: u32, sub_bucket_index: u32) -> u64 {
+        (sub_bucket_index as u64) << (bucket_index + self.unit_magnitude)
+    }
+}
@@ -1,10 +1,14 @@
 pub mod recording;
+pub mod histogram;
 mod platform;
 
-#[cfg(test)] 
+#[cfg(test)]
 mod tests;
 
-pub use recording::{recv, try_recv, RegionRecord, ThreadProfile, RegionExecution, ToChromeTracing};
+pub use recording::{
+    recv, try_recv, start_periodic_flush, CounterSample, RegionRecord, ThreadProfile, RegionExecution, RegionStats, ToChromeTracing, ToDot,
+    ToInfluxLineProtocol,
+};
 
 #[macro_export]
 macro_rules! region {
@@ -12,3 +16,10 @@ macro_rules! region {
         let _region = $crate::recording::RegionRecord::new($name, file!(), line!());
     }
 }
+
+#[macro_export]
+macro_rules! counter {
+    ($name: expr, $value: expr) => {
+        $crate::recording::record_counter($name, $value as f64);
+    }
+}
@@ -3,24 +3,28 @@ use std::{
     collections::{BTreeMap, VecDeque},
     io::{self, Write},
     rc::Rc,
-    sync::{Condvar, Mutex},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
 };
 
+use crate::histogram::Histogram;
 use crate::platform::RecordingTimestamp;
 
+use crossbeam_channel::{Receiver, Sender};
 use lazy_static::lazy_static;
 use time::Duration;
 
-#[derive(Default)]
-struct Global {
-    pub profiles: VecDeque<Box<RawThreadProfile>>,
-}
-
 lazy_static! {
-    static ref GLOBAL: Mutex<Global> = Mutex::new(Global::default());
-    static ref CONDVAR: Condvar = Condvar::new();
+    static ref CHANNEL: (Sender<Box<RawThreadProfile>>, Receiver<Box<RawThreadProfile>>) = crossbeam_channel::unbounded();
 }
 
+/// Bumped once per interval by the background thread spawned from
+/// [`start_periodic_flush`]. Stays at `0` unless that function is called (it
+/// is process-wide, so one call makes every thread flush), and until then the
+/// per-call check in [`flush_current_thread_if_stale`] never finds a thread
+/// stale and never flushes anything.
+static FLUSH_TICK: AtomicU64 = AtomicU64::new(0);
+
 thread_local! {
     static THREAD_LOCAL: RefCell<ThreadLocal> = RefCell::new(ThreadLocal::new());
 }
@@ -29,6 +33,7 @@ thread_local! {
 pub struct RawThreadProfile {
     pub thread_id: usize,
     pub region_backends: Vec<RegionRecordBackend>,
+    pub counter_samples: Vec<RawCounterSample>,
 }
 
 impl RawThreadProfile {
@@ -36,14 +41,23 @@ impl RawThreadProfile {
         Self {
             thread_id,
             region_backends: Vec::with_capacity(1024),
+            counter_samples: Vec::new(),
         }
     }
 }
 
+#[derive(Debug)]
+pub struct RawCounterSample {
+    pub name: &'static str,
+    pub value: f64,
+    pub timestamp: RecordingTimestamp,
+}
+
 #[derive(Debug)]
 struct ThreadLocal {
     raw_thread_profile: Option<Box<RawThreadProfile>>,
     stack: VecDeque<usize>,
+    last_flush_tick: u64,
 }
 
 impl ThreadLocal {
@@ -51,6 +65,7 @@ impl ThreadLocal {
         Self {
             raw_thread_profile: Some(Box::new(RawThreadProfile::new(thread_id::get()))),
             stack: VecDeque::new(),
+            last_flush_tick: 0,
         }
     }
 }
@@ -91,6 +106,7 @@ impl RegionRecord {
                 });
             }
         });
+        flush_current_thread_if_stale();
         RegionRecord
     }
 }
@@ -114,19 +130,39 @@ impl Drop for RegionRecord {
 
             // If the stack is empty the regions can be send to the global collection point
             if thread_local.stack.len() == 0 {
-                let mut g = GLOBAL.lock().unwrap();
                 let raw_thread_profile = thread_local
                     .raw_thread_profile
                     .take()
                     .expect("there must be a raw_thread_profile when drop is executed");
-                g.profiles.push_back(raw_thread_profile);
-                drop(g);
-                CONDVAR.notify_one();
+                CHANNEL.0.send(raw_thread_profile).expect("the receiver is never dropped while the process is running");
             }
         });
+        flush_current_thread_if_stale();
     }
 }
 
+/// Records a numeric sample (queue depth, bytes processed, cache hits, ...)
+/// at the current point in time on the current thread. Used by the
+/// [`crate::counter!`] macro; see [`ToChromeTracing`] for how samples are
+/// serialized as Chrome Tracing "C" (counter) events.
+#[inline]
+pub fn record_counter(name: &'static str, value: f64) {
+    THREAD_LOCAL.with(|thread_local| {
+        let mut thread_local = thread_local.borrow_mut();
+        if thread_local.raw_thread_profile.is_none() {
+            thread_local.raw_thread_profile = Some(Box::new(RawThreadProfile::new(thread_id::get())));
+        }
+        if let Some(ref mut raw_thread_profile) = thread_local.raw_thread_profile {
+            raw_thread_profile.counter_samples.push(RawCounterSample {
+                name,
+                value,
+                timestamp: RecordingTimestamp::now(),
+            });
+        }
+    });
+    flush_current_thread_if_stale();
+}
+
 #[derive(Debug)]
 pub struct RegionRecordBackend {
     pub name: &'static str,
@@ -138,16 +174,113 @@ pub struct RegionRecordBackend {
 }
 
 pub fn recv() -> Box<RawThreadProfile> {
-    let mut g = GLOBAL.lock().unwrap();
-    while g.profiles.is_empty() {
-        g = CONDVAR.wait(g).unwrap();
-    }
-    g.profiles.pop_front().expect("is_empty equals false")
+    CHANNEL.1.recv().expect("the sender is never dropped while the process is running")
 }
 
 pub fn try_recv() -> Option<Box<RawThreadProfile>> {
-    let mut g = GLOBAL.lock().unwrap();
-    g.profiles.pop_front()
+    CHANNEL.1.try_recv().ok()
+}
+
+/// Snapshots the completed (end-is-some) region backends and all counter
+/// samples out of `thread_local`, remapping parent indices so the retained,
+/// still-open regions stay internally consistent. Returns the partial
+/// profile to ship, or `None` if nothing has completed since the last flush.
+fn flush_thread_local(thread_local: &mut ThreadLocal) -> Option<Box<RawThreadProfile>> {
+    let raw_thread_profile = thread_local.raw_thread_profile.as_mut()?;
+
+    let open_indices: std::collections::HashSet<usize> = thread_local.stack.iter().cloned().collect();
+    if open_indices.len() == raw_thread_profile.region_backends.len() && raw_thread_profile.counter_samples.is_empty() {
+        return None;
+    }
+
+    let thread_id = raw_thread_profile.thread_id;
+    let old_backends = std::mem::replace(&mut raw_thread_profile.region_backends, Vec::with_capacity(1024));
+    let counter_samples = std::mem::take(&mut raw_thread_profile.counter_samples);
+
+    let mut completed_old_to_new: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut retained_old_to_new: BTreeMap<usize, usize> = BTreeMap::new();
+    for old_index in 0..old_backends.len() {
+        if open_indices.contains(&old_index) {
+            retained_old_to_new.insert(old_index, retained_old_to_new.len());
+        } else {
+            completed_old_to_new.insert(old_index, completed_old_to_new.len());
+        }
+    }
+
+    let mut completed_backends = Vec::with_capacity(completed_old_to_new.len());
+    let mut retained_backends = Vec::with_capacity(retained_old_to_new.len());
+    for (old_index, mut backend) in old_backends.into_iter().enumerate() {
+        let is_retained = open_indices.contains(&old_index);
+        backend.parent = match backend.parent {
+            None => None,
+            // Ancestors of a still-open region are always still open themselves.
+            Some(parent_index) if is_retained => retained_old_to_new.get(&parent_index).copied(),
+            // A completed region whose parent is still open becomes a root of the partial profile.
+            Some(parent_index) => completed_old_to_new.get(&parent_index).copied(),
+        };
+        if is_retained {
+            retained_backends.push(backend);
+        } else {
+            completed_backends.push(backend);
+        }
+    }
+
+    raw_thread_profile.region_backends = retained_backends;
+    thread_local.stack = thread_local
+        .stack
+        .iter()
+        .map(|old_index| *retained_old_to_new.get(old_index).expect("entries on the stack are still open"))
+        .collect();
+
+    if completed_backends.is_empty() && counter_samples.is_empty() {
+        return None;
+    }
+    Some(Box::new(RawThreadProfile {
+        thread_id,
+        region_backends: completed_backends,
+        counter_samples,
+    }))
+}
+
+/// Checks whether [`FLUSH_TICK`] has advanced since this thread last flushed
+/// and, if so, flushes it. There is no cross-thread registry: each thread
+/// notices the tick and flushes itself the next time it enters/exits a
+/// region or records a counter, so the background thread in
+/// [`start_periodic_flush`] never touches another thread's state and threads
+/// that come and go don't need to be tracked or pruned anywhere.
+#[inline]
+fn flush_current_thread_if_stale() {
+    let tick = FLUSH_TICK.load(Ordering::Relaxed);
+    THREAD_LOCAL.with(|thread_local| {
+        let mut thread_local = thread_local.borrow_mut();
+        if thread_local.last_flush_tick == tick {
+            return;
+        }
+        thread_local.last_flush_tick = tick;
+        if let Some(partial_profile) = flush_thread_local(&mut thread_local) {
+            CHANNEL.0.send(partial_profile).expect("the receiver is never dropped while the process is running");
+        }
+    });
+}
+
+/// Starts a background thread that, on a configurable interval, marks every
+/// thread's profile as due for a flush. This lets a thread whose top-level
+/// region lives for the whole process (a server's main loop, say) still
+/// surface data through its nested regions, instead of only doing so once
+/// that top-level region finally drops. The tick is shared by all threads, so
+/// one call turns flushing on for every thread; until it is called, the tick
+/// check on the hot path never triggers a flush.
+pub fn start_periodic_flush(interval: std::time::Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        FLUSH_TICK.fetch_add(1, Ordering::Relaxed);
+    });
+}
+
+#[cfg(test)]
+pub(crate) fn force_flush_current_thread_for_test() {
+    FLUSH_TICK.fetch_add(1, Ordering::Relaxed);
+    flush_current_thread_if_stale();
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -207,11 +340,19 @@ impl RegionExecution {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct CounterSample {
+    pub name: &'static str,
+    pub value: f64,
+    pub timestamp: Instant,
+}
+
 #[derive(Debug)]
 pub struct ThreadProfile {
     pub thread_id: usize,
     pub regions: BTreeMap<Rc<Region>, Vec<RegionExecution>>,
     pub root_region_executions: Vec<RegionExecution>,
+    pub counter_samples: Vec<CounterSample>,
 }
 
 impl RawThreadProfile {
@@ -236,10 +377,20 @@ impl RawThreadProfile {
             let root_region = self.generate_region_execution(region_backend, *root_region_index, &children_indices, &mut regions);
             root_region_executions.push(root_region);
         }
+        let counter_samples = self
+            .counter_samples
+            .iter()
+            .map(|counter_sample| CounterSample {
+                name: counter_sample.name,
+                value: counter_sample.value,
+                timestamp: Instant::new(counter_sample.timestamp.to_nanoseconds()),
+            })
+            .collect();
         ThreadProfile {
             thread_id: self.thread_id,
             regions,
             root_region_executions,
+            counter_samples,
         }
     }
 
@@ -282,6 +433,80 @@ impl RawThreadProfile {
     }
 }
 
+/// Significant figures kept by the HDR histograms backing [`RegionStats`].
+const REGION_HISTOGRAM_SIGNIFICANT_FIGURES: u8 = 3;
+
+/// A latency summary for every recorded execution of one [`Region`].
+#[derive(Debug, Clone)]
+pub struct RegionStats {
+    pub count: u64,
+    pub min_nanos: u64,
+    pub max_nanos: u64,
+    pub mean_nanos: f64,
+    pub p50_nanos: u64,
+    pub p90_nanos: u64,
+    pub p99_nanos: u64,
+    pub p999_nanos: u64,
+}
+
+impl RegionStats {
+    fn from_histogram(histogram: &Histogram) -> Self {
+        Self {
+            count: histogram.total_count(),
+            min_nanos: histogram.min(),
+            max_nanos: histogram.max(),
+            mean_nanos: histogram.mean(),
+            p50_nanos: histogram.percentile(50.0),
+            p90_nanos: histogram.percentile(90.0),
+            p99_nanos: histogram.percentile(99.0),
+            p999_nanos: histogram.percentile(99.9),
+        }
+    }
+}
+
+fn record_region_executions<'a>(
+    region_executions: impl IntoIterator<Item = &'a RegionExecution>,
+    histograms: &mut BTreeMap<Rc<Region>, Histogram>,
+) {
+    for region_execution in region_executions {
+        histograms
+            .entry(region_execution.region.clone())
+            .or_insert_with(|| Histogram::new(REGION_HISTOGRAM_SIGNIFICANT_FIGURES))
+            .record(region_execution.duration().whole_nanoseconds() as u64);
+    }
+}
+
+impl ThreadProfile {
+    /// Aggregates every execution of each [`Region`] into an HDR-histogram-backed
+    /// [`RegionStats`], so regions called thousands of times (e.g. a tight inner
+    /// loop) get a compact statistical view instead of the raw `Vec` in `regions`.
+    pub fn region_histograms(&self) -> BTreeMap<Rc<Region>, RegionStats> {
+        let mut histograms = BTreeMap::new();
+        for region_executions in self.regions.values() {
+            record_region_executions(region_executions, &mut histograms);
+        }
+        histograms
+            .iter()
+            .map(|(region, histogram)| (region.clone(), RegionStats::from_histogram(histogram)))
+            .collect()
+    }
+}
+
+/// Merges the per-`Region` histograms of several [`ThreadProfile`]s (for example
+/// a batch drained via repeated [`recv`] calls) into one combined summary.
+pub fn merge_region_histograms<'a>(thread_profiles: impl IntoIterator<Item = &'a ThreadProfile>) -> BTreeMap<Rc<Region>, RegionStats> {
+    let mut histograms = BTreeMap::new();
+    for thread_profile in thread_profiles {
+        for region_executions in thread_profile.regions.values() {
+            record_region_executions(region_executions, &mut histograms);
+        }
+    }
+    histograms
+        .iter()
+        .map(|(region, histogram)| (region.clone(), RegionStats::from_histogram(histogram)))
+        .collect()
+}
+
 fn traverse<W: Write>(region_execution: &RegionExecution, target: &mut W, pid: u32, tid: usize) -> io::Result<()> {
     let start = region_execution.start.nanoseconds as f64 / 1000.0;
     let duration = (region_execution.end.nanoseconds - region_execution.start.nanoseconds) as f64 / 1000.0;
@@ -305,12 +530,162 @@ pub trait ToChromeTracing {
     fn to_chrome_tracing<W: Write>(&self, target: &mut W) -> io::Result<()>;
 }
 
+fn write_counter_sample<W: Write>(counter_sample: &CounterSample, target: &mut W, pid: u32, tid: usize) -> io::Result<()> {
+    let ts = counter_sample.timestamp.as_nanoseconds() / 1000.0;
+    let mut args = json::JsonValue::new_object();
+    args[counter_sample.name] = counter_sample.value.into();
+    let data = json::object! {
+        name: counter_sample.name,
+        ph: "C",
+        ts: ts,
+        pid: pid,
+        tid: tid,
+        args: args,
+    };
+    target.write_all(json::stringify(data).as_bytes())?;
+    target.write_all(b",")?;
+    Ok(())
+}
+
 impl ToChromeTracing for ThreadProfile {
     fn to_chrome_tracing<W: Write>(&self, target: &mut W) -> io::Result<()> {
         let pid = std::process::id();
         for region_execution in &self.root_region_executions {
             traverse(region_execution, target, pid, self.thread_id)?;
         }
+        for counter_sample in &self.counter_samples {
+            write_counter_sample(counter_sample, target, pid, self.thread_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Escapes a line-protocol tag value by backslash-escaping commas, spaces and
+/// equals signs, per https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/.
+fn escape_influx_tag_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,").replace(' ', "\\ ").replace('=', "\\=")
+}
+
+fn traverse_influx<W: Write>(region_execution: &RegionExecution, target: &mut W, pid: u32, tid: usize, depth: u64) -> io::Result<()> {
+    let name = escape_influx_tag_value(region_execution.region.name);
+    let file = escape_influx_tag_value(region_execution.region.file);
+    let start_ns = region_execution.start.nanoseconds as i64;
+    let duration_ns = region_execution.duration().whole_nanoseconds() as i64;
+    writeln!(
+        target,
+        "region,thread_id={},pid={},name={},file={} duration_ns={}i,start_ns={}i,depth={}i {}",
+        tid, pid, name, file, duration_ns, start_ns, depth, start_ns
+    )?;
+    for child_region_execution in &region_execution.children {
+        traverse_influx(child_region_execution, target, pid, tid, depth + 1)?;
+    }
+    Ok(())
+}
+
+/// Serializes a [`ThreadProfile`] as InfluxDB line protocol, one `region`
+/// measurement per [`RegionExecution`], so profiles can be streamed into a
+/// time-series database alongside (or instead of) [`ToChromeTracing`] output.
+pub trait ToInfluxLineProtocol {
+    fn to_influx_line_protocol<W: Write>(&self, target: &mut W) -> io::Result<()>;
+}
+
+impl ToInfluxLineProtocol for ThreadProfile {
+    fn to_influx_line_protocol<W: Write>(&self, target: &mut W) -> io::Result<()> {
+        let pid = std::process::id();
+        for region_execution in &self.root_region_executions {
+            traverse_influx(region_execution, target, pid, self.thread_id, 0)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct DotNode {
+    count: u64,
+    total_duration_nanos: i64,
+}
+
+#[derive(Default)]
+struct DotEdge {
+    count: u64,
+    total_duration_nanos: i64,
+}
+
+fn escape_dot_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn dot_node_id(region: &Region) -> String {
+    escape_dot_label(&format!("{}:{}:{}", region.name, region.file, region.line))
+}
+
+fn fold_dot(
+    region_execution: &RegionExecution,
+    parent: Option<&Rc<Region>>,
+    nodes: &mut BTreeMap<Rc<Region>, DotNode>,
+    edges: &mut BTreeMap<(Rc<Region>, Rc<Region>), DotEdge>,
+) {
+    let region = &region_execution.region;
+    let duration_nanos = region_execution.duration().whole_nanoseconds() as i64;
+
+    let node = nodes.entry(region.clone()).or_default();
+    node.count += 1;
+    node.total_duration_nanos += duration_nanos;
+
+    if let Some(parent_region) = parent {
+        let edge = edges.entry((parent_region.clone(), region.clone())).or_default();
+        edge.count += 1;
+        edge.total_duration_nanos += duration_nanos;
+    }
+
+    for child_region_execution in &region_execution.children {
+        fold_dot(child_region_execution, Some(region), nodes, edges);
+    }
+}
+
+/// Renders a [`ThreadProfile`] as a Graphviz `digraph`: one node per unique
+/// [`Region`] (labeled with name, file:line, call count and total/mean
+/// duration) and one `->` edge per parent/child relationship (labeled with
+/// how often it occurred and the summed child duration). Recursive or
+/// repeatedly-called regions collapse into a single node, with recursion
+/// showing up as a self-edge.
+pub trait ToDot {
+    fn to_dot<W: Write>(&self, target: &mut W) -> io::Result<()>;
+}
+
+impl ToDot for ThreadProfile {
+    fn to_dot<W: Write>(&self, target: &mut W) -> io::Result<()> {
+        let mut nodes: BTreeMap<Rc<Region>, DotNode> = BTreeMap::new();
+        let mut edges: BTreeMap<(Rc<Region>, Rc<Region>), DotEdge> = BTreeMap::new();
+        for root_region_execution in &self.root_region_executions {
+            fold_dot(root_region_execution, None, &mut nodes, &mut edges);
+        }
+
+        writeln!(target, "digraph thread_{} {{", self.thread_id)?;
+        for (region, node) in &nodes {
+            let mean_nanos = node.total_duration_nanos as f64 / node.count as f64;
+            let label = format!(
+                "{}\\n{}:{}\\ncount={}\\ntotal={:.1}us\\nmean={:.1}us",
+                escape_dot_label(region.name),
+                escape_dot_label(region.file),
+                region.line,
+                node.count,
+                node.total_duration_nanos as f64 / 1000.0,
+                mean_nanos / 1000.0,
+            );
+            writeln!(target, "    \"{}\" [label=\"{}\"];", dot_node_id(region), label)?;
+        }
+        for ((parent, child), edge) in &edges {
+            writeln!(
+                target,
+                "    \"{}\" -> \"{}\" [label=\"count={} total={:.1}us\"];",
+                dot_node_id(parent),
+                dot_node_id(child),
+                edge.count,
+                edge.total_duration_nanos as f64 / 1000.0,
+            )?;
+        }
+        writeln!(target, "}}")?;
         Ok(())
     }
 }
@@ -114,4 +114,148 @@ mod profiling {
         let profile = result.profile();
         assert_eq!(profile.root_region_executions.len(), 1);
     }
+
+    #[test]
+    #[serial]
+    fn region_histograms_summarize_repeated_region() {
+        const COUNT: usize = 2_000;
+        fn function1() {
+            let _region = instrument::region!("function1");
+            thread::sleep(Duration::from_millis(1));
+        }
+        fn main() {
+            let _region = instrument::region!("main");
+            for _ in 0..COUNT {
+                function1();
+            }
+        }
+        main();
+        let result = instrument::recv();
+        assert!(instrument::try_recv().is_none());
+        let profile = result.profile();
+        let histograms = profile.region_histograms();
+        assert_eq!(histograms.len(), 2);
+        let ref function1_stats = histograms
+            .iter()
+            .find(|(region, _)| region.name == "function1")
+            .expect("function1 must have been recorded")
+            .1;
+        assert_eq!(function1_stats.count, COUNT as u64);
+        assert!(function1_stats.min_nanos <= function1_stats.p50_nanos);
+        assert!(function1_stats.p50_nanos <= function1_stats.p90_nanos);
+        assert!(function1_stats.p90_nanos <= function1_stats.p99_nanos);
+        assert!(function1_stats.p99_nanos <= function1_stats.p999_nanos);
+        assert!(function1_stats.p999_nanos <= function1_stats.max_nanos);
+    }
+
+    #[test]
+    #[serial]
+    fn to_influx_line_protocol_escapes_tag_values_and_carries_depth() {
+        use instrument::ToInfluxLineProtocol;
+
+        fn child() {
+            let _region = instrument::region!("child task, retry");
+            sleep();
+        }
+        fn main() {
+            let _region = instrument::region!("main task");
+            child();
+        }
+        main();
+        let result = instrument::recv();
+        let profile = result.profile();
+
+        let mut buffer = Vec::new();
+        profile.to_influx_line_protocol(&mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = text.lines().filter(|line| !line.is_empty()).collect();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("region,"));
+        assert!(lines[0].contains(r"name=main\ task"));
+        assert!(lines[0].contains("depth=0i"));
+        assert!(lines[1].contains(r"name=child\ task\,\ retry"));
+        assert!(lines[1].contains("depth=1i"));
+        assert!(instrument::try_recv().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn to_dot_collapses_repeated_regions_into_one_node_with_a_self_edge() {
+        use instrument::ToDot;
+
+        fn recurse(depth: u32) {
+            let _region = instrument::region!("recurse \"deep\"");
+            if depth > 0 {
+                recurse(depth - 1);
+            }
+        }
+        recurse(2);
+        let result = instrument::recv();
+        let profile = result.profile();
+
+        let mut buffer = Vec::new();
+        profile.to_dot(&mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+
+        assert!(text.starts_with("digraph thread_"));
+
+        let node_lines: Vec<&str> = text.lines().filter(|line| line.contains("[label=") && !line.contains("->")).collect();
+        assert_eq!(node_lines.len(), 1, "the recursive region must collapse into a single node");
+        assert!(node_lines[0].contains(r#"recurse \"deep\""#), "the quote in the region name must be escaped");
+
+        let edge_lines: Vec<&str> = text.lines().filter(|line| line.contains("->")).collect();
+        assert_eq!(edge_lines.len(), 1, "the recursive calls must collapse into a single self-edge");
+        assert!(edge_lines[0].contains("count=2"));
+        let (source_id, rest) = edge_lines[0].split_once("->").expect("edge line must have an edgeop");
+        let target_id = rest.split('[').next().expect("edge line must have a target");
+        assert_eq!(source_id.trim(), target_id.trim(), "a recursive region must produce a self-edge");
+
+        assert!(instrument::try_recv().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn counter_samples_are_recorded_alongside_regions() {
+        fn main() {
+            let _region = instrument::region!("main");
+            instrument::counter!("queue_depth", 3);
+            instrument::counter!("queue_depth", 7);
+        }
+        main();
+        let raw_thread_profile = instrument::recv();
+        assert_eq!(raw_thread_profile.counter_samples.len(), 2);
+        let profile = raw_thread_profile.profile();
+        assert_eq!(profile.counter_samples.len(), 2);
+        assert_eq!(profile.counter_samples[0].name, "queue_depth");
+        assert_eq!(profile.counter_samples[0].value, 3.0);
+        assert_eq!(profile.counter_samples[1].value, 7.0);
+        assert!(instrument::try_recv().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn periodic_flush_surfaces_completed_children_of_a_long_lived_root() {
+        fn function1() {
+            let _region = instrument::region!("function1");
+            sleep();
+        }
+        let _root = instrument::region!("root");
+        function1();
+
+        crate::recording::force_flush_current_thread_for_test();
+
+        let flushed = instrument::recv();
+        assert_eq!(flushed.region_backends.len(), 1);
+        assert_eq!(flushed.region_backends[0].name, "function1");
+        assert!(flushed.region_backends[0].parent.is_none());
+        assert!(instrument::try_recv().is_none());
+
+        drop(_root);
+
+        let root_profile = instrument::recv();
+        assert_eq!(root_profile.region_backends.len(), 1);
+        assert_eq!(root_profile.region_backends[0].name, "root");
+        assert!(instrument::try_recv().is_none());
+    }
 }